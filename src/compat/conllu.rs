@@ -1,11 +1,16 @@
+use std::borrow::Cow;
 use std::convert::TryFrom;
 
-use conllu::graph::Sentence;
+use conllu::graph::{DepTriple, Sentence};
 use conllu::token::Features;
 
 use crate::layer::{Layer, LayerValue};
 use crate::lemma::Lemmas;
 
+/// Symbol used for `HeadRelativePosition` when a token is attached
+/// to the virtual root, for which no relative distance is defined.
+const ROOT_RELATIVE_POSITION: &str = "root";
+
 impl LayerValue for Sentence {
     fn form(&self, idx: usize) -> &str {
         let node = &self[idx];
@@ -43,33 +48,75 @@ impl LayerValue for Sentence {
             Layer::Misc { feature, .. } => {
                 token.misc_mut().insert(feature.clone(), Some(value));
             }
+            Layer::Deprel => {
+                let head = self
+                    .dep_graph()
+                    .head(idx)
+                    .map(|triple| triple.head)
+                    .unwrap_or(0);
+                self.dep_graph_mut()
+                    .add_deprel(DepTriple::new(head, idx, Some(value)));
+            }
+            Layer::HeadRelativePosition { .. } => {
+                let head = if value == ROOT_RELATIVE_POSITION {
+                    0
+                } else {
+                    let offset: isize = value
+                        .parse()
+                        .expect("Invalid head-relative-position representation");
+                    let head = idx as isize + offset;
+                    usize::try_from(head)
+                        .ok()
+                        .filter(|&head| head > 0 && head < self.len())
+                        .expect("Head-relative-position offset points outside of the sentence")
+                };
+                let relation = self.dep_graph().head(idx).and_then(|triple| triple.relation);
+                self.dep_graph_mut()
+                    .add_deprel(DepTriple::new(head, idx, relation));
+            }
         };
     }
 
-    fn value(&self, idx: usize, layer: &Layer) -> Option<String> {
+    fn value(&self, idx: usize, layer: &Layer) -> Option<Cow<str>> {
         let node = &self[idx];
         assert!(node.is_token(), "Attempted to get value from root node");
         let token = self[idx].token().unwrap();
 
         match layer {
-            Layer::UPos => token.upos().map(ToOwned::to_owned),
-            Layer::XPos => token.xpos().map(ToOwned::to_owned),
-            Layer::FeatureString => Some(token.features().into()),
+            Layer::UPos => token.upos().map(Cow::Borrowed),
+            Layer::XPos => token.xpos().map(Cow::Borrowed),
+            Layer::FeatureString => Some(Cow::Owned(token.features().into())),
             Layer::Feature { feature, default } => token
                 .features()
                 .get(feature)
-                .cloned()
-                .or_else(|| default.clone()),
+                .map(|value| Cow::Borrowed(value.as_str()))
+                .or_else(|| default.clone().map(Cow::Owned)),
             Layer::Misc { feature, default } => match token.misc().get(feature) {
                 // Feature with an associated value.
-                Some(Some(ref val)) => Some(val.clone()),
+                Some(Some(ref val)) => Some(Cow::Borrowed(val.as_str())),
 
                 // Feature without an associated value, should not be used.
                 Some(None) => None,
 
                 // The feature is absent.
-                None => default.clone(),
+                None => default.clone().map(Cow::Owned),
             },
+            Layer::Deprel => self
+                .dep_graph()
+                .head(idx)
+                .and_then(|triple| triple.relation)
+                .map(Cow::Owned),
+            Layer::HeadRelativePosition { distance } => {
+                self.dep_graph().head(idx).map(|triple| {
+                    if triple.head == 0 {
+                        Cow::Borrowed(ROOT_RELATIVE_POSITION)
+                    } else {
+                        let offset = triple.head as isize - idx as isize;
+                        let distance = *distance as isize;
+                        Cow::Owned(offset.max(-distance).min(distance).to_string())
+                    }
+                })
+            }
         }
     }
 }
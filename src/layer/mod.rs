@@ -1,5 +1,8 @@
 //! CoNLL-X layer encoder.
 
+use std::borrow::Cow;
+use std::io::{Read, Write};
+
 use failure::Error;
 use serde_derive::{Deserialize, Serialize};
 
@@ -33,6 +36,23 @@ pub enum Layer {
         // Default value if the feature is absent.
         default: Option<String>,
     },
+
+    /// The dependency relation to the head.
+    Deprel,
+
+    /// The position of the head, relative to the token.
+    ///
+    /// The encoded value is the signed distance (in tokens) between
+    /// a token and its head, clamped to `[-distance, distance]`.
+    /// Since a distance is undefined for the virtual root, tokens
+    /// attached to the root are assigned the distinguished symbol
+    /// `root` instead.
+    #[serde(rename = "head_relative_position")]
+    HeadRelativePosition {
+        /// The maximum left/right distance. Distances that are
+        /// larger than the window are clamped to `distance`.
+        distance: usize,
+    },
 }
 
 impl Layer {
@@ -45,6 +65,12 @@ impl Layer {
     pub fn misc(feature: String, default: Option<String>) -> Self {
         Layer::Misc { feature, default }
     }
+
+    /// Construct a head-relative-position layer with the given
+    /// clamping window.
+    pub fn head_relative_position(distance: usize) -> Self {
+        Layer::HeadRelativePosition { distance }
+    }
 }
 
 /// Layer values.
@@ -60,7 +86,12 @@ pub trait LayerValue {
     fn set_value(&mut self, idx: usize, layer: &Layer, value: impl Into<String>);
 
     /// Get a layer value.
-    fn value(&self, idx: usize, layer: &Layer) -> Option<String>;
+    ///
+    /// Values that are already stored as a string in the sentence
+    /// (e.g. `UPos`, `XPos`) are returned as a borrow to avoid an
+    /// allocation; values that have to be constructed (e.g.
+    /// `FeatureString`, defaults) are returned owned.
+    fn value(&self, idx: usize, layer: &Layer) -> Option<Cow<str>>;
 }
 
 /// Encode sentences using a CoNLL-X layer.
@@ -74,13 +105,98 @@ impl LayerEncoder {
     pub fn new(layer: Layer) -> Self {
         LayerEncoder { layer }
     }
+
+    /// Serialize this encoder, together with its label vocabulary, to CBOR.
+    pub fn to_cbor<W>(&self, labels: &[String], writer: W) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        let envelope = Envelope {
+            version: ENVELOPE_VERSION,
+            config: self,
+            labels: labels.to_vec(),
+        };
+
+        serde_cbor::to_writer(writer, &envelope)?;
+
+        Ok(())
+    }
+
+    /// Deserialize an encoder and its label vocabulary from CBOR.
+    ///
+    /// Fails if the envelope's version differs from the version this
+    /// crate writes. The returned labels are not compared against
+    /// any other label inventory.
+    pub fn from_cbor<R>(reader: R) -> Result<(Self, Vec<String>), Error>
+    where
+        R: Read,
+    {
+        let envelope: Envelope<Self> = serde_cbor::from_reader(reader)?;
+
+        if envelope.version != ENVELOPE_VERSION {
+            return Err(failure::format_err!(
+                "unsupported encoder envelope version: expected {}, found {}",
+                ENVELOPE_VERSION,
+                envelope.version
+            ));
+        }
+
+        Ok((envelope.config, envelope.labels))
+    }
+
+    /// Encode a sentence without allocating when a layer's value is
+    /// already stored as a string in the sentence (e.g. `UPos`,
+    /// `XPos`).
+    ///
+    /// This is an additive convenience for callers who consume the
+    /// encoding immediately and can therefore work with borrowed
+    /// data; it does not change the behavior of
+    /// [`SentenceEncoder::encode`]. `SentenceEncoder::Encoding` has
+    /// no lifetime parameter (adding one is a larger, cross-cutting
+    /// change to that trait, which lives outside this module), so
+    /// going through the trait — the path used to collect encodings
+    /// across a corpus for training — always materializes one owned
+    /// `String` per label, regardless of whether the underlying
+    /// value was borrowable. Call this method directly to avoid
+    /// that allocation.
+    pub fn encode_borrowed<'a, L>(&self, sentence: &'a L) -> Result<Vec<Cow<'a, str>>, Error>
+    where
+        L: LayerValue,
+    {
+        let mut encoding = Vec::with_capacity(sentence.len() - 1);
+
+        for idx in 1..sentence.len() {
+            let label =
+                sentence
+                    .value(idx, &self.layer)
+                    .ok_or_else(|| EncodeError::MissingLabel {
+                        form: sentence.form(idx).to_owned(),
+                    })?;
+            encoding.push(label);
+        }
+
+        Ok(encoding)
+    }
+}
+
+/// On-disk envelope version for [`Envelope`].
+const ENVELOPE_VERSION: u32 = 1;
+
+/// A versioned, binary-serializable envelope pairing an encoder
+/// configuration with the label vocabulary observed at training
+/// time.
+#[derive(Deserialize, Serialize)]
+struct Envelope<C> {
+    version: u32,
+    config: C,
+    labels: Vec<String>,
 }
 
 impl<L> SentenceDecoder<L> for LayerEncoder
 where
     L: LayerValue,
 {
-    type Encoding = String;
+    type Encoding = Cow<'static, str>;
 
     fn decode<S>(&self, labels: &[S], sentence: &mut L) -> Result<(), Error>
     where
@@ -94,7 +210,7 @@ where
 
         for (idx, label) in labels.iter().enumerate() {
             if let Some(label) = label.as_ref().get(0) {
-                sentence.set_value(idx + 1, &self.layer, label.encoding().as_str());
+                sentence.set_value(idx + 1, &self.layer, label.encoding().clone());
             }
         }
 
@@ -106,19 +222,156 @@ impl<L> SentenceEncoder<L> for LayerEncoder
 where
     L: LayerValue,
 {
-    type Encoding = String;
+    type Encoding = Cow<'static, str>;
+
+    fn encode(&self, sentence: &L) -> Result<Vec<Self::Encoding>, Error> {
+        Ok(self
+            .encode_borrowed(sentence)?
+            .into_iter()
+            .map(|label| Cow::Owned(label.into_owned()))
+            .collect())
+    }
+}
+
+/// Encode several layers as a single composite label.
+///
+/// Each layer's value is packed as a length-prefixed field
+/// (`<presence><length>:<value>`, followed by `separator`), so that
+/// a layer's own value is never mistaken for "absent" (e.g.
+/// `FeatureString`'s legitimate empty representation, `"_"`), and so
+/// that fields are split by their recorded byte length rather than
+/// by searching for `separator` inside the value — a value
+/// containing `separator` (again, `FeatureString` is a natural
+/// example: `"a=b|c=d"`) still round-trips correctly. This lets e.g.
+/// `UPos` and a handful of `Feature` layers be predicted jointly as
+/// one label, instead of running a separate [`LayerEncoder`] per
+/// layer.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CompositeLayerEncoder {
+    layers: Vec<Layer>,
+    separator: String,
+}
+
+impl CompositeLayerEncoder {
+    /// Construct a composite encoder for the given layers, joined
+    /// using `separator`.
+    pub fn new(layers: Vec<Layer>, separator: impl Into<String>) -> Self {
+        CompositeLayerEncoder {
+            layers,
+            separator: separator.into(),
+        }
+    }
+}
+
+/// Parse one length-prefixed field off the front of `packed`.
+///
+/// A field has the form `<presence><length>:<value>`, immediately
+/// followed by `separator`, where `presence` is `1` for a layer that
+/// had a value and `0` for a layer whose value was absent (in which
+/// case `length` is always `0` and `value` is empty). Returns
+/// whether the field was present, its value, and the remainder of
+/// `packed` after the field and its trailing separator. Returns
+/// `None` if `packed` does not contain a complete, well-formed
+/// field (in particular, if it is empty).
+fn split_composite_field<'a>(packed: &'a str, separator: &str) -> Option<(bool, &'a str, &'a str)> {
+    let mut chars = packed.char_indices();
+    let (_, presence_char) = chars.next()?;
+    let present = match presence_char {
+        '1' => true,
+        '0' => false,
+        _ => return None,
+    };
+
+    let after_presence = &packed[presence_char.len_utf8()..];
+    let colon = after_presence.find(':')?;
+    let len: usize = after_presence[..colon].parse().ok()?;
+
+    let value_start = colon + 1;
+    let value_end = value_start.checked_add(len)?;
+    let value = after_presence.get(value_start..value_end)?;
+
+    let after_value = &after_presence[value_end..];
+    let remainder = after_value.strip_prefix(separator)?;
+
+    Some((present, value, remainder))
+}
+
+impl<L> SentenceDecoder<L> for CompositeLayerEncoder
+where
+    L: LayerValue,
+{
+    type Encoding = Cow<'static, str>;
+
+    fn decode<S>(&self, labels: &[S], sentence: &mut L) -> Result<(), Error>
+    where
+        S: AsRef<[EncodingProb<Self::Encoding>]>,
+    {
+        assert_eq!(
+            labels.len(),
+            sentence.len() - 1,
+            "Labels and sentence length mismatch"
+        );
+
+        for (idx, label) in labels.iter().enumerate() {
+            if let Some(label) = label.as_ref().get(0) {
+                let mut rest: &str = label.encoding().as_ref();
+
+                for layer in &self.layers {
+                    let (present, value, remainder) =
+                        split_composite_field(rest, &self.separator).ok_or_else(|| {
+                            failure::format_err!(
+                                "composite label has fewer fields than the {} configured layers",
+                                self.layers.len()
+                            )
+                        })?;
+
+                    if present {
+                        sentence.set_value(idx + 1, layer, value);
+                    }
+
+                    rest = remainder;
+                }
+
+                if !rest.is_empty() {
+                    return Err(failure::format_err!(
+                        "composite label has more fields than the {} configured layers",
+                        self.layers.len()
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<L> SentenceEncoder<L> for CompositeLayerEncoder
+where
+    L: LayerValue,
+{
+    type Encoding = Cow<'static, str>;
 
     fn encode(&self, sentence: &L) -> Result<Vec<Self::Encoding>, Error> {
+        use std::fmt::Write as _;
+
         let mut encoding = Vec::with_capacity(sentence.len() - 1);
 
         for idx in 1..sentence.len() {
-            let label =
-                sentence
-                    .value(idx, &self.layer)
-                    .ok_or_else(|| EncodeError::MissingLabel {
-                        form: sentence.form(idx).to_owned(),
-                    })?;
-            encoding.push(label.to_owned());
+            let mut packed = String::new();
+
+            for layer in &self.layers {
+                match sentence.value(idx, layer) {
+                    Some(value) => {
+                        write!(packed, "1{}:{}", value.len(), value)
+                            .expect("Writing to a String cannot fail");
+                    }
+                    None => packed.push_str("00:"),
+                }
+
+                packed.push_str(&self.separator);
+            }
+
+            encoding.push(Cow::Owned(packed));
         }
 
         Ok(encoding)
@@ -127,13 +380,15 @@ where
 
 #[cfg(test)]
 mod tests {
+    use std::borrow::Cow;
     use std::convert::TryFrom;
     use std::iter::FromIterator;
 
-    use conllu::graph::Sentence;
+    use conllu::graph::{DepTriple, Sentence};
     use conllu::token::{Features, Misc, Token, TokenBuilder};
 
-    use crate::layer::{Layer, LayerValue};
+    use crate::layer::{CompositeLayerEncoder, Layer, LayerEncoder, LayerValue};
+    use crate::{EncodingProb, SentenceDecoder, SentenceEncoder};
 
     #[test]
     fn layer() {
@@ -146,15 +401,15 @@ mod tests {
 
         let sent = Sentence::from_iter(vec![token]);
 
-        assert_eq!(sent.value(1, &Layer::UPos), Some("CP".to_string()));
-        assert_eq!(sent.value(1, &Layer::XPos), Some("P".to_string()));
+        assert_eq!(sent.value(1, &Layer::UPos), Some(Cow::Borrowed("CP")));
+        assert_eq!(sent.value(1, &Layer::XPos), Some(Cow::Borrowed("P")));
         assert_eq!(
             sent.value(1, &Layer::feature("a".to_owned(), None)),
-            Some("b".to_string())
+            Some(Cow::Borrowed("b"))
         );
         assert_eq!(
             sent.value(1, &Layer::feature("c".to_owned(), None)),
-            Some("d".to_string())
+            Some(Cow::Borrowed("d"))
         );
         assert_eq!(sent.value(1, &Layer::feature("e".to_owned(), None)), None);
         assert_eq!(
@@ -162,20 +417,20 @@ mod tests {
                 1,
                 &Layer::feature("e".to_owned(), Some("some_default".to_string()))
             ),
-            Some("some_default".to_string())
+            Some(Cow::Borrowed("some_default"))
         );
         assert_eq!(
             sent.value(1, &Layer::FeatureString),
-            Some("a=b|c=d".to_string())
+            Some(Cow::Borrowed("a=b|c=d"))
         );
 
         assert_eq!(
             sent.value(1, &Layer::misc("u".to_owned(), None)),
-            Some("v".to_string())
+            Some(Cow::Borrowed("v"))
         );
         assert_eq!(
             sent.value(1, &Layer::misc("x".to_owned(), None)),
-            Some("y".to_string())
+            Some(Cow::Borrowed("y"))
         );
         assert_eq!(sent.value(1, &Layer::misc("z".to_owned(), None)), None);
         assert_eq!(
@@ -183,7 +438,7 @@ mod tests {
                 1,
                 &Layer::misc("z".to_owned(), Some("some_default".to_string()))
             ),
-            Some("some_default".to_string())
+            Some(Cow::Borrowed("some_default"))
         );
     }
 
@@ -192,29 +447,224 @@ mod tests {
         let token: Token = TokenBuilder::new("test").into();
         let mut sent = Sentence::from_iter(vec![token]);
 
-        assert_eq!(sent.value(1, &Layer::FeatureString), Some("_".to_string()));
+        assert_eq!(sent.value(1, &Layer::FeatureString), Some(Cow::Borrowed("_")));
 
         sent.set_value(1, &Layer::UPos, "CP");
         sent.set_value(1, &Layer::XPos, "P");
         sent.set_value(1, &Layer::feature("a".to_owned(), None), "b");
         sent.set_value(1, &Layer::misc("u".to_owned(), None), "v");
 
-        assert_eq!(sent.value(1, &Layer::UPos), Some("CP".to_string()));
-        assert_eq!(sent.value(1, &Layer::XPos), Some("P".to_string()));
+        assert_eq!(sent.value(1, &Layer::UPos), Some(Cow::Borrowed("CP")));
+        assert_eq!(sent.value(1, &Layer::XPos), Some(Cow::Borrowed("P")));
         assert_eq!(
             sent.value(1, &Layer::feature("a".to_owned(), None)),
-            Some("b".to_string())
+            Some(Cow::Borrowed("b"))
         );
         assert_eq!(sent.value(1, &Layer::feature("c".to_owned(), None)), None);
         assert_eq!(
             sent.value(1, &Layer::FeatureString),
-            Some("a=b".to_string())
+            Some(Cow::Borrowed("a=b"))
         );
 
         assert_eq!(
             sent.value(1, &Layer::misc("u".to_owned(), None)),
-            Some("v".to_string())
+            Some(Cow::Borrowed("v"))
         );
         assert_eq!(sent.value(1, &Layer::misc("x".to_owned(), None)), None);
     }
+
+    #[test]
+    fn deprel_layer() {
+        let tokens = vec![
+            TokenBuilder::new("a").into(),
+            TokenBuilder::new("b").into(),
+        ];
+        let mut sent = Sentence::from_iter(tokens);
+
+        sent.dep_graph_mut()
+            .add_deprel(DepTriple::new(0, 1, Some("root".to_string())));
+        sent.dep_graph_mut()
+            .add_deprel(DepTriple::new(1, 2, Some("obj".to_string())));
+
+        assert_eq!(sent.value(1, &Layer::Deprel), Some(Cow::Borrowed("root")));
+        assert_eq!(sent.value(2, &Layer::Deprel), Some(Cow::Borrowed("obj")));
+
+        assert_eq!(
+            sent.value(1, &Layer::head_relative_position(3)),
+            Some(Cow::Borrowed("root"))
+        );
+        assert_eq!(
+            sent.value(2, &Layer::head_relative_position(3)),
+            Some(Cow::Borrowed("-1"))
+        );
+
+        sent.set_value(2, &Layer::Deprel, "nsubj");
+        assert_eq!(sent.value(2, &Layer::Deprel), Some(Cow::Borrowed("nsubj")));
+
+        sent.set_value(2, &Layer::head_relative_position(3), "root");
+        assert_eq!(sent.value(2, &Layer::Deprel), Some(Cow::Borrowed("nsubj")));
+        assert_eq!(
+            sent.value(2, &Layer::head_relative_position(3)),
+            Some(Cow::Borrowed("root"))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "outside of the sentence")]
+    fn head_relative_position_out_of_bounds_offset_panics() {
+        let tokens = vec![
+            TokenBuilder::new("a").into(),
+            TokenBuilder::new("b").into(),
+            TokenBuilder::new("c").into(),
+        ];
+        let mut sent = Sentence::from_iter(tokens);
+
+        sent.set_value(1, &Layer::head_relative_position(10), "5");
+    }
+
+    #[test]
+    #[should_panic(expected = "outside of the sentence")]
+    fn head_relative_position_undershoot_does_not_become_root() {
+        let tokens = vec![
+            TokenBuilder::new("a").into(),
+            TokenBuilder::new("b").into(),
+            TokenBuilder::new("c").into(),
+        ];
+        let mut sent = Sentence::from_iter(tokens);
+
+        sent.set_value(1, &Layer::head_relative_position(10), "-5");
+    }
+
+    #[test]
+    fn encode_borrowed_does_not_allocate_for_upos() {
+        let token: Token = TokenBuilder::new("test").upos("CP").into();
+        let sent = Sentence::from_iter(vec![token]);
+
+        let encoder = LayerEncoder::new(Layer::UPos);
+        let encoding = encoder.encode_borrowed(&sent).unwrap();
+
+        assert_eq!(encoding, vec![Cow::Borrowed("CP")]);
+        assert!(matches!(encoding[0], Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn cbor_roundtrip() {
+        let encoder = LayerEncoder::new(Layer::feature("a".to_owned(), None));
+        let labels = vec!["b".to_string(), "d".to_string()];
+
+        let mut buffer = Vec::new();
+        encoder.to_cbor(&labels, &mut buffer).unwrap();
+
+        let (decoded, decoded_labels) = LayerEncoder::from_cbor(buffer.as_slice()).unwrap();
+
+        assert_eq!(decoded, encoder);
+        assert_eq!(decoded_labels, labels);
+    }
+
+    #[test]
+    fn composite_layer_encoder() {
+        let encoder = CompositeLayerEncoder::new(
+            vec![Layer::UPos, Layer::feature("a".to_owned(), None)],
+            "|",
+        );
+
+        let token: Token = TokenBuilder::new("test")
+            .upos("CP")
+            .features(Features::try_from("a=b").unwrap())
+            .into();
+        let sent = Sentence::from_iter(vec![token]);
+
+        let encoding = SentenceEncoder::encode(&encoder, &sent).unwrap();
+        assert_eq!(encoding, vec![Cow::Borrowed("12:CP|11:b|")]);
+
+        let token: Token = TokenBuilder::new("test").upos("CP").into();
+        let mut missing_feature_sent = Sentence::from_iter(vec![token]);
+        let encoding = SentenceEncoder::encode(&encoder, &missing_feature_sent).unwrap();
+        assert_eq!(encoding, vec![Cow::Borrowed("12:CP|00:|")]);
+
+        let labels = vec![vec![EncodingProb::new(encoding[0].clone(), 1.0)]];
+        SentenceDecoder::decode(&encoder, &labels, &mut missing_feature_sent).unwrap();
+        assert_eq!(
+            missing_feature_sent.value(1, &Layer::UPos),
+            Some(Cow::Borrowed("CP"))
+        );
+        assert_eq!(
+            missing_feature_sent.value(1, &Layer::feature("a".to_owned(), None)),
+            None
+        );
+    }
+
+    #[test]
+    fn composite_layer_encoder_does_not_confuse_feature_string_null_with_absent() {
+        let encoder = CompositeLayerEncoder::new(vec![Layer::UPos, Layer::FeatureString], "|");
+
+        // A token with no features legitimately encodes `FeatureString`
+        // as conllu's own null-value representation, `"_"`.
+        let token: Token = TokenBuilder::new("test").upos("CP").into();
+        let sent = Sentence::from_iter(vec![token]);
+        assert_eq!(sent.value(1, &Layer::FeatureString), Some(Cow::Borrowed("_")));
+
+        let encoding = SentenceEncoder::encode(&encoder, &sent).unwrap();
+        let labels = vec![vec![EncodingProb::new(encoding[0].clone(), 1.0)]];
+
+        // Decode onto a sentence that already carries (stale) features
+        // for this token.
+        let stale_token: Token = TokenBuilder::new("test")
+            .features(Features::try_from("a=b").unwrap())
+            .into();
+        let mut target = Sentence::from_iter(vec![stale_token]);
+        assert_eq!(
+            target.value(1, &Layer::FeatureString),
+            Some(Cow::Borrowed("a=b"))
+        );
+
+        SentenceDecoder::decode(&encoder, &labels, &mut target).unwrap();
+
+        // The predicted, genuinely empty feature string must overwrite
+        // the stale features, rather than being treated as absent and
+        // skipped.
+        assert_eq!(
+            target.value(1, &Layer::FeatureString),
+            Some(Cow::Borrowed("_"))
+        );
+    }
+
+    #[test]
+    fn composite_layer_encoder_value_containing_separator_roundtrips() {
+        // `FeatureString`'s natural representation can itself contain
+        // the configured separator; the packed field format must not
+        // get confused by this.
+        let encoder = CompositeLayerEncoder::new(vec![Layer::UPos, Layer::FeatureString], "|");
+
+        let token: Token = TokenBuilder::new("test")
+            .upos("CP")
+            .features(Features::try_from("c=d|a=b").unwrap())
+            .into();
+        let sent = Sentence::from_iter(vec![token]);
+
+        let encoding = SentenceEncoder::encode(&encoder, &sent).unwrap();
+        let labels = vec![vec![EncodingProb::new(encoding[0].clone(), 1.0)]];
+
+        let target_token: Token = TokenBuilder::new("test").into();
+        let mut target = Sentence::from_iter(vec![target_token]);
+        SentenceDecoder::decode(&encoder, &labels, &mut target).unwrap();
+
+        assert_eq!(target.value(1, &Layer::UPos), Some(Cow::Borrowed("CP")));
+        assert_eq!(
+            target.value(1, &Layer::FeatureString),
+            Some(Cow::Borrowed("a=b|c=d"))
+        );
+    }
+
+    #[test]
+    fn composite_layer_encoder_rejects_field_count_mismatch() {
+        let encoder = CompositeLayerEncoder::new(vec![Layer::UPos, Layer::XPos], "|");
+
+        let token: Token = TokenBuilder::new("test").into();
+        let mut sent = Sentence::from_iter(vec![token]);
+
+        // Only one packed field, but the encoder expects two.
+        let labels = vec![vec![EncodingProb::new(Cow::Borrowed("12:CP|"), 1.0)]];
+        assert!(SentenceDecoder::decode(&encoder, &labels, &mut sent).is_err());
+    }
 }